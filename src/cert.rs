@@ -0,0 +1,102 @@
+//! Loading certificates and private keys from disk, auto-detecting PEM vs.
+//! raw DER so the tool interoperates with the usual cert-issuing tooling
+//! instead of requiring everything pre-converted to DER.
+
+use std::path::Path;
+
+use color_eyre::eyre::eyre;
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+/// Reads a certificate (or chain) from `path`. PEM files (`-----BEGIN
+/// CERTIFICATE-----`) may contain more than one; DER files are assumed to
+/// hold exactly one.
+pub fn read_certs(path: &Path) -> color_eyre::Result<Vec<Certificate>> {
+    let bytes = std::fs::read(path)?;
+    if is_pem(&bytes) {
+        let ders = rustls_pemfile::certs(&mut &bytes[..])?;
+        Ok(ders.into_iter().map(Certificate).collect())
+    } else {
+        Ok(vec![Certificate(bytes)])
+    }
+}
+
+/// Reads a single certificate from `path`, erroring if the file holds none.
+pub fn read_cert(path: &Path) -> color_eyre::Result<Certificate> {
+    read_certs(path)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre!("{} contains no certificate", path.display()))
+}
+
+/// Reads a private key from `path`, accepting PKCS#8, RSA and EC PEM blocks
+/// as well as raw DER.
+pub fn read_private_key(path: &Path) -> color_eyre::Result<PrivateKey> {
+    let bytes = std::fs::read(path)?;
+    if !is_pem(&bytes) {
+        return Ok(PrivateKey(bytes));
+    }
+
+    let mut reader = &bytes[..];
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(eyre!("{} contains no private key", path.display())),
+        }
+    }
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"-----BEGIN")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_pem_detects_pem_and_der() {
+        assert!(is_pem(b"-----BEGIN CERTIFICATE-----\n..."));
+        assert!(!is_pem(&[0x30, 0x82, 0x01, 0x0a]));
+    }
+
+    #[test]
+    fn reads_pem_and_der_cert_chains() {
+        let cert = rcgen::generate_simple_self_signed(vec!["".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let pem = cert.serialize_pem().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let der_path = dir.path().join("cert.der");
+        std::fs::write(&der_path, &der).unwrap();
+        let from_der = read_cert(&der_path).unwrap();
+        assert_eq!(from_der.0, der);
+
+        let pem_path = dir.path().join("cert.pem");
+        std::fs::write(&pem_path, &pem).unwrap();
+        let from_pem = read_cert(&pem_path).unwrap();
+        assert_eq!(from_pem.0, der);
+    }
+
+    #[test]
+    fn reads_pem_and_der_private_keys() {
+        let cert = rcgen::generate_simple_self_signed(vec!["".to_string()]).unwrap();
+        let der = cert.serialize_private_key_der();
+        let pem = cert.serialize_private_key_pem();
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let der_path = dir.path().join("key.der");
+        std::fs::write(&der_path, &der).unwrap();
+        assert_eq!(read_private_key(&der_path).unwrap().0, der);
+
+        let pem_path = dir.path().join("key.pem");
+        std::fs::write(&pem_path, &pem).unwrap();
+        assert_eq!(read_private_key(&pem_path).unwrap().0, der);
+    }
+}
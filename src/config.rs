@@ -0,0 +1,82 @@
+//! Building TLS configs from the cert/key files on disk, plus a background
+//! watcher that keeps an `ArcSwap` up to date so a long-running server or
+//! client can pick up rotated certificates without restarting.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use tokio_rustls::rustls::{ClientConfig, ServerConfig};
+
+use crate::cert;
+use crate::verifier::{PeerVerifier, PinMode};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn build_server_config(
+    server_cert: &Path,
+    server_private_key: &Path,
+    client_pin: &PinMode,
+) -> color_eyre::Result<ServerConfig> {
+    let server_cert_chain = cert::read_certs(server_cert)?;
+    let server_private_key = cert::read_private_key(server_private_key)?;
+
+    let client_cert_verifier = Arc::new(PeerVerifier::new(client_pin)?);
+
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_cert_verifier)
+        .with_single_cert(server_cert_chain, server_private_key)?)
+}
+
+pub fn build_client_config(
+    client_cert: &Path,
+    client_private_key: &Path,
+    server_pin: &PinMode,
+) -> color_eyre::Result<ClientConfig> {
+    let client_cert_chain = cert::read_certs(client_cert)?;
+    let client_private_key = cert::read_private_key(client_private_key)?;
+
+    let server_cert_verifier = Arc::new(PeerVerifier::new(server_pin)?);
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(server_cert_verifier)
+        .with_client_auth_cert(client_cert_chain, client_private_key)?)
+}
+
+/// Watches `paths` for mtime changes and calls `rebuild` to refresh `swap`
+/// whenever any of them change. Connections that already grabbed a config
+/// keep using it; only new handshakes observe the swap.
+pub fn watch_and_reload<T, F>(swap: Arc<ArcSwap<T>>, paths: Vec<PathBuf>, rebuild: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn() -> color_eyre::Result<T> + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_modified = mtimes(&paths);
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let modified = mtimes(&paths);
+            if modified != last_modified {
+                match rebuild() {
+                    Ok(config) => {
+                        swap.store(Arc::new(config));
+                        last_modified = modified;
+                    }
+                    Err(err) => {
+                        eprintln!("failed to reload TLS config, keeping old one: {err}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn mtimes(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
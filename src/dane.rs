@@ -0,0 +1,179 @@
+//! DANE/TLSA verification (RFC 6698): instead of shipping a pinned
+//! certificate, validate the peer against the TLSA records published at
+//! `_<port>._tcp.<host>`. A background task refreshes the cached records
+//! every [`CACHE_TTL`] so a handshake never blocks on DNS, except for the
+//! very first lookup performed when the verifier is constructed.
+//!
+//! This only checks the resolver's AD bit, not the DNSSEC chain itself, so
+//! it's only as trustworthy as whatever resolver `/etc/resolv.conf` (or
+//! [`FALLBACK_RESOLVER`]) points at; run a local validating resolver if
+//! that matters for your deployment.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use color_eyre::eyre::eyre;
+use hickory_client::client::{Client, SyncClient};
+use hickory_client::rr::rdata::tlsa::{CertUsage, Matching, Selector, TLSA};
+use hickory_client::rr::{DNSClass, Name, RData, RecordType};
+use hickory_client::tcp::TcpClientConnection;
+use hickory_client::udp::UdpClientConnection;
+use sha2::{Digest, Sha256, Sha512};
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{Certificate, CertificateError, Error as TlsError, ServerName};
+
+use crate::verifier::spki_der;
+
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Used when `/etc/resolv.conf` doesn't name a nameserver we can parse.
+const FALLBACK_RESOLVER: &str = "1.1.1.1:53";
+
+pub struct DaneVerifier {
+    host: String,
+    port: u16,
+    cache: Arc<ArcSwap<Vec<TLSA>>>,
+}
+
+impl DaneVerifier {
+    pub fn new(host: String, port: u16) -> color_eyre::Result<Self> {
+        // The very first lookup blocks verifier construction (there's
+        // nothing to cache yet); every lookup after that is handled by the
+        // background task below, so a handshake never blocks on DNS.
+        let records = tokio::task::block_in_place(|| query_tlsa(&host, port))?;
+        let cache = Arc::new(ArcSwap::from_pointee(records));
+
+        let refresh_host = host.clone();
+        let refresh_cache = cache.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CACHE_TTL).await;
+                match tokio::task::block_in_place(|| query_tlsa(&refresh_host, port)) {
+                    Ok(records) => refresh_cache.store(Arc::new(records)),
+                    Err(err) => eprintln!(
+                        "failed to refresh TLSA records for _{port}._tcp.{refresh_host}, keeping old ones: {err}"
+                    ),
+                }
+            }
+        });
+
+        Ok(Self { host, port, cache })
+    }
+
+    fn records(&self) -> Arc<Vec<TLSA>> {
+        self.cache.load_full()
+    }
+}
+
+impl ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let records = self.records();
+        if records.is_empty() {
+            return Err(TlsError::General(format!(
+                "no TLSA records for _{}._tcp.{}",
+                self.port, self.host
+            )));
+        }
+
+        // DANE-EE records pin the end-entity certificate itself, so the
+        // intermediates are never matched against them.
+        if records.iter().any(|record| record_matches(record, end_entity)) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        Err(TlsError::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+}
+
+fn record_matches(record: &TLSA, cert: &Certificate) -> bool {
+    // Only usage 3 (DANE-EE/domain-issued) is a direct pin against the
+    // presented certificate. Usages 0-2 require building and validating a
+    // chain to a CA, which this tool doesn't do, so they're rejected rather
+    // than silently treated as a pin they don't actually authorize.
+    if record.cert_usage() != CertUsage::DomainIssued {
+        return false;
+    }
+
+    let selected = match record.selector() {
+        Selector::Full => cert.0.clone(),
+        Selector::Spki => match spki_der(cert) {
+            Ok(spki) => spki,
+            Err(_) => return false,
+        },
+        Selector::Unknown(_) => return false,
+    };
+
+    let computed = match record.matching() {
+        Matching::Raw => selected,
+        Matching::Sha256 => Sha256::digest(&selected).to_vec(),
+        Matching::Sha512 => Sha512::digest(&selected).to_vec(),
+        Matching::Unknown(_) => return false,
+    };
+
+    computed == record.cert_data()
+}
+
+fn query_tlsa(host: &str, port: u16) -> color_eyre::Result<Vec<TLSA>> {
+    let resolver = system_resolver();
+    let name = Name::from_ascii(format!("_{port}._tcp.{host}."))?;
+
+    let response = {
+        let conn = UdpClientConnection::new(resolver)?;
+        let client = SyncClient::new(conn);
+        client.query(&name, DNSClass::IN, RecordType::TLSA)?
+    };
+
+    // A host publishing more than one (or large, SHA-512) TLSA record won't
+    // fit in a UDP datagram; a truncated response means we have to redo the
+    // query over TCP to actually see every record.
+    let response = if response.header().truncated() {
+        let conn = TcpClientConnection::new(resolver)?;
+        let client = SyncClient::new(conn);
+        client.query(&name, DNSClass::IN, RecordType::TLSA)?
+    } else {
+        response
+    };
+
+    // DANE is only as trustworthy as the DNSSEC validation backing it: a
+    // network attacker able to forge DNS responses can forge TLSA records
+    // too. We don't validate the DNSSEC chain ourselves, so this only checks
+    // that the resolver claims to have done so (the AD bit) - `--verify
+    // dane` is only as secure as the configured system resolver.
+    if !response.header().authentic_data() {
+        return Err(eyre!(
+            "resolver did not authenticate the TLSA records for _{port}._tcp.{host} (AD bit unset); \
+             --verify dane requires a DNSSEC-validating resolver"
+        ));
+    }
+
+    Ok(response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::TLSA(tlsa)) => Some(tlsa.clone()),
+            _ => None,
+        })
+        .collect())
+}
+
+fn system_resolver() -> std::net::SocketAddr {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines()
+                .filter_map(|line| line.strip_prefix("nameserver "))
+                .find_map(|addr| addr.trim().parse::<std::net::IpAddr>().ok())
+        })
+        .map(|ip| std::net::SocketAddr::new(ip, 53))
+        .unwrap_or_else(|| FALLBACK_RESOLVER.parse().expect("valid fallback resolver"))
+}
@@ -1,73 +1,31 @@
+mod cert;
+mod config;
+mod dane;
+mod mux;
+mod pool;
+mod tunnel;
+mod verifier;
+
 use std::net::{Ipv4Addr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 
+use arc_swap::ArcSwap;
 use clap::Parser;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
-use tokio_rustls::rustls::server::{ClientCertVerified, ClientCertVerifier};
-use tokio_rustls::rustls::{
-    Certificate, CertificateError, ClientConfig, DistinguishedName, PrivateKey, ServerConfig,
-    ServerName,
-};
+use tokio_rustls::rustls::ServerName;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 
-struct SingleCertVerifier {
-    certificate: Certificate,
-    distinguished_names: Vec<DistinguishedName>,
-}
-
-impl SingleCertVerifier {
-    fn new(certificate: Certificate) -> Self {
-        Self {
-            certificate,
-            distinguished_names: vec![DistinguishedName::from(vec![])],
-        }
-    }
-}
-
-impl ClientCertVerifier for SingleCertVerifier {
-    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
-        &self.distinguished_names
-    }
-
-    fn verify_client_cert(
-        &self,
-        end_entity: &Certificate,
-        _intermediates: &[Certificate],
-        _now: std::time::SystemTime,
-    ) -> Result<ClientCertVerified, tokio_rustls::rustls::Error> {
-        if end_entity == &self.certificate {
-            Ok(ClientCertVerified::assertion())
-        } else {
-            Err(tokio_rustls::rustls::Error::InvalidCertificate(
-                CertificateError::ApplicationVerificationFailure,
-            ))
-        }
-    }
-}
+use crate::pool::TunnelPool;
+use crate::tunnel::Tunnel;
 
-impl ServerCertVerifier for SingleCertVerifier {
-    fn verify_server_cert(
-        &self,
-        end_entity: &Certificate,
-        _intermediates: &[Certificate],
-        _server_name: &tokio_rustls::rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
-        if end_entity == &self.certificate {
-            Ok(ServerCertVerified::assertion())
-        } else {
-            Err(tokio_rustls::rustls::Error::InvalidCertificate(
-                CertificateError::ApplicationVerificationFailure,
-            ))
-        }
-    }
-}
+/// How long an incoming connection waits for a pooled tunnel before it's
+/// dropped.
+const POOL_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Parser)]
 struct Cli {
@@ -75,9 +33,19 @@ struct Cli {
     command: Command,
 }
 
+/// Alternative to pinning a value locally: validate the peer against DNS.
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum VerifyMode {
+    Dane,
+}
+
 #[derive(clap::Subcommand)]
 enum Command {
-    Generate,
+    Generate {
+        /// Write PEM-encoded certs/keys instead of raw DER.
+        #[arg(long)]
+        pem: bool,
+    },
     Server {
         #[arg(long)]
         proxy_port: u16,
@@ -91,8 +59,20 @@ enum Command {
         #[arg(long)]
         server_private_key: PathBuf,
 
+        /// Pin the client to this exact certificate. Mutually exclusive with
+        /// `--pin-spki-sha256` and `--tofu-pin-file`.
         #[arg(long)]
-        client_cert: PathBuf,
+        client_cert: Option<PathBuf>,
+
+        /// Pin the client by the SHA-256 hash (hex) of its public key
+        /// instead of its whole certificate.
+        #[arg(long)]
+        pin_spki_sha256: Option<String>,
+
+        /// Trust the client's public key the first time it's seen, record
+        /// its fingerprint here, and refuse any other key afterwards.
+        #[arg(long)]
+        tofu_pin_file: Option<PathBuf>,
     },
     Client {
         #[arg(long)]
@@ -107,8 +87,29 @@ enum Command {
         #[arg(long)]
         client_private_key: PathBuf,
 
+        /// Pin the server to this exact certificate. Mutually exclusive with
+        /// `--pin-spki-sha256` and `--tofu-pin-file`.
         #[arg(long)]
-        server_cert: PathBuf,
+        server_cert: Option<PathBuf>,
+
+        /// Pin the server by the SHA-256 hash (hex) of its public key
+        /// instead of its whole certificate.
+        #[arg(long)]
+        pin_spki_sha256: Option<String>,
+
+        /// Trust the server's public key the first time it's seen, record
+        /// its fingerprint here, and refuse any other key afterwards.
+        #[arg(long)]
+        tofu_pin_file: Option<PathBuf>,
+
+        /// Validate the server against DNS (TLSA records) instead of a
+        /// locally pinned certificate or fingerprint.
+        #[arg(long)]
+        verify: Option<VerifyMode>,
+
+        /// Number of spare tunnels to keep connected to the server.
+        #[arg(long, default_value_t = 1)]
+        pool_size: usize,
     },
 }
 
@@ -118,16 +119,24 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     match args.command {
-        Command::Generate => {
+        Command::Generate { pem } => {
             for name in ["server", "client"] {
                 let cert = rcgen::generate_simple_self_signed(vec!["".to_string()])?;
                 std::fs::create_dir_all("certs")?;
 
-                std::fs::write(format!("certs/{name}_cert.der"), cert.serialize_der()?)?;
-                std::fs::write(
-                    format!("certs/{name}_key.der"),
-                    cert.serialize_private_key_der(),
-                )?;
+                if pem {
+                    std::fs::write(format!("certs/{name}_cert.pem"), cert.serialize_pem()?)?;
+                    std::fs::write(
+                        format!("certs/{name}_key.pem"),
+                        cert.serialize_private_key_pem(),
+                    )?;
+                } else {
+                    std::fs::write(format!("certs/{name}_cert.der"), cert.serialize_der()?)?;
+                    std::fs::write(
+                        format!("certs/{name}_key.der"),
+                        cert.serialize_private_key_der(),
+                    )?;
+                }
             }
         }
         Command::Server {
@@ -136,37 +145,73 @@ async fn main() -> Result<()> {
             server_cert,
             server_private_key,
             client_cert,
+            pin_spki_sha256,
+            tofu_pin_file,
         } => {
-            let server_cert = Certificate(std::fs::read(server_cert)?);
-            let server_private_key = PrivateKey(std::fs::read(server_private_key)?);
-            let client_cert = Certificate(std::fs::read(client_cert)?);
+            let client_pin =
+                verifier::PinMode::from_args(client_cert, pin_spki_sha256, tofu_pin_file, None)?;
 
-            let client_cert_verifier = Arc::new(SingleCertVerifier::new(client_cert));
+            let config =
+                config::build_server_config(&server_cert, &server_private_key, &client_pin)?;
+            let config = Arc::new(ArcSwap::from_pointee(config));
 
-            let config = ServerConfig::builder()
-                .with_safe_defaults()
-                .with_client_cert_verifier(client_cert_verifier)
-                .with_single_cert(vec![server_cert], server_private_key)?;
+            let mut watched_paths = vec![server_cert.clone(), server_private_key.clone()];
+            if let verifier::PinMode::FullCert(path) = &client_pin {
+                watched_paths.push(path.clone());
+            }
 
-            let acceptor = TlsAcceptor::from(Arc::new(config));
+            config::watch_and_reload(config.clone(), watched_paths, move || {
+                config::build_server_config(&server_cert, &server_private_key, &client_pin)
+            });
 
             let proxy_listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, proxy_port)).await?;
 
             let incoming_listener =
                 TcpListener::bind((Ipv4Addr::UNSPECIFIED, incoming_port)).await?;
 
-            loop {
-                let ((proxy_stream, _), (mut incoming_stream, _)) =
-                    tokio::try_join!(proxy_listener.accept(), incoming_listener.accept())?;
-                let acceptor = acceptor.clone();
-                tokio::spawn(async move {
-                    let mut proxy_stream = acceptor.accept(proxy_stream).await?;
-
-                    tokio::io::copy_bidirectional(&mut proxy_stream, &mut incoming_stream).await?;
-
-                    Ok::<_, color_eyre::Report>(())
-                });
-            }
+            // Idle, already-handshaked tunnels the client has proactively
+            // opened. Incoming connections pop one immediately instead of
+            // waiting on `proxy_listener.accept()` to happen to fire at the
+            // same time.
+            let pool = TunnelPool::new();
+
+            let accept_proxy = {
+                let pool = pool.clone();
+                async move {
+                    loop {
+                        let (proxy_stream, _) = proxy_listener.accept().await?;
+                        let acceptor = TlsAcceptor::from(config.load_full());
+                        let proxy_stream = acceptor.accept(proxy_stream).await?;
+                        pool.push(Tunnel::new(proxy_stream)).await;
+                    }
+                    #[allow(unreachable_code)]
+                    Ok::<(), color_eyre::Report>(())
+                }
+            };
+
+            let relay_incoming = async move {
+                loop {
+                    let (mut incoming_stream, _) = incoming_listener.accept().await?;
+
+                    let Some(tunnel) = pool.pop_with_timeout(POOL_WAIT_TIMEOUT).await else {
+                        continue;
+                    };
+                    // The tunnel is multiplexed, so it can keep serving other
+                    // streams while this one is in flight.
+                    pool.push(tunnel.clone()).await;
+
+                    tokio::spawn(async move {
+                        let mut mux_stream = tunnel.open_stream().await;
+                        tokio::io::copy_bidirectional(&mut mux_stream, &mut incoming_stream)
+                            .await?;
+                        Ok::<_, color_eyre::Report>(())
+                    });
+                }
+                #[allow(unreachable_code)]
+                Ok::<(), color_eyre::Report>(())
+            };
+
+            tokio::try_join!(accept_proxy, relay_incoming)?;
         }
         Command::Client {
             proxy_host,
@@ -174,35 +219,85 @@ async fn main() -> Result<()> {
             client_cert,
             client_private_key,
             server_cert,
+            pin_spki_sha256,
+            tofu_pin_file,
+            verify,
+            pool_size,
         } => {
-            let client_cert = Certificate(std::fs::read(client_cert)?);
-            let client_private_key = PrivateKey(std::fs::read(client_private_key)?);
-            let server_cert = Certificate(std::fs::read(server_cert)?);
-
-            let server_cert_verifier = Arc::new(SingleCertVerifier::new(server_cert));
-            let config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_custom_certificate_verifier(server_cert_verifier)
-                .with_client_auth_cert(vec![client_cert], client_private_key)?;
+            let dane_target = match verify {
+                Some(VerifyMode::Dane) => {
+                    let (host, port) = proxy_host
+                        .rsplit_once(':')
+                        .ok_or_else(|| eyre!("--proxy-host must be host:port for --verify dane"))?;
+                    Some((host.to_string(), port.parse()?))
+                }
+                None => None,
+            };
+            let server_pin = verifier::PinMode::from_args(
+                server_cert,
+                pin_spki_sha256,
+                tofu_pin_file,
+                dane_target,
+            )?;
+
+            let config =
+                config::build_client_config(&client_cert, &client_private_key, &server_pin)?;
+            let config = Arc::new(ArcSwap::from_pointee(config));
+
+            let mut watched_paths = vec![client_cert.clone(), client_private_key.clone()];
+            if let verifier::PinMode::FullCert(path) = &server_pin {
+                watched_paths.push(path.clone());
+            }
 
-            let connector = TlsConnector::from(Arc::new(config));
+            config::watch_and_reload(config.clone(), watched_paths, move || {
+                config::build_client_config(&client_cert, &client_private_key, &server_pin)
+            });
 
             let proxy_host: Vec<_> = proxy_host.to_socket_addrs()?.collect();
             let outgoing_host: Vec<_> = outgoing_host.to_socket_addrs()?.collect();
 
             let domain = ServerName::try_from("secure-tcp-gender-changer")?;
 
-            loop {
-                let proxy_stream = TcpStream::connect(&*proxy_host).await?;
-                let mut proxy_stream = connector.connect(domain.clone(), proxy_stream).await?;
+            // Keep `pool_size` tunnels dialed in at all times; each runs
+            // independently and re-dials if it ever drops.
+            let mut spares = Vec::with_capacity(pool_size.max(1));
+            for _ in 0..pool_size.max(1) {
+                let config = config.clone();
+                let proxy_host = proxy_host.clone();
                 let outgoing_host = outgoing_host.clone();
-                tokio::spawn(async move {
-                    let mut outgoing_stream = TcpStream::connect(&*outgoing_host).await?;
-
-                    tokio::io::copy_bidirectional(&mut outgoing_stream, &mut proxy_stream).await?;
+                let domain = domain.clone();
+                spares.push(tokio::spawn(async move {
+                    loop {
+                        let proxy_stream = TcpStream::connect(&*proxy_host).await?;
+                        let connector = TlsConnector::from(config.load_full());
+                        let proxy_stream =
+                            connector.connect(domain.clone(), proxy_stream).await?;
+                        let tunnel = Tunnel::new(proxy_stream);
+
+                        while let Some(mut mux_stream) = tunnel.accept_stream().await {
+                            let outgoing_host = outgoing_host.clone();
+                            tokio::spawn(async move {
+                                let mut outgoing_stream =
+                                    TcpStream::connect(&*outgoing_host).await?;
+
+                                tokio::io::copy_bidirectional(
+                                    &mut outgoing_stream,
+                                    &mut mux_stream,
+                                )
+                                .await?;
+
+                                Ok::<_, color_eyre::Report>(())
+                            });
+                        }
+                        // The tunnel died; redial to keep the pool full.
+                    }
+                    #[allow(unreachable_code)]
+                    Ok::<(), color_eyre::Report>(())
+                }));
+            }
 
-                    Ok::<_, color_eyre::Report>(())
-                });
+            for spare in spares {
+                spare.await??;
             }
         }
     }
@@ -0,0 +1,132 @@
+//! Frame protocol used to multiplex many logical streams over one tunnel.
+//!
+//! Each frame on the wire is `[u32 stream_id][u8 type][u32 len][payload]`,
+//! big-endian. `Open` asks the peer to start relaying a new logical stream,
+//! `Data` carries bytes for an existing one, and `Close` half-closes it.
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const TYPE_OPEN: u8 = 0;
+const TYPE_DATA: u8 = 1;
+const TYPE_CLOSE: u8 = 2;
+
+/// Largest payload a single frame may carry. `len` is attacker-controlled
+/// (read straight off the wire before we've authenticated anything about the
+/// sender's intent), so it's checked before allocating a buffer for it --
+/// otherwise a single crafted frame claiming a `len` near `u32::MAX` would
+/// force a multi-gigabyte allocation.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub enum FrameBody {
+    Open,
+    Data(Bytes),
+    Close,
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub stream_id: u32,
+    pub body: FrameBody,
+}
+
+impl Frame {
+    pub fn open(stream_id: u32) -> Self {
+        Self {
+            stream_id,
+            body: FrameBody::Open,
+        }
+    }
+
+    pub fn data(stream_id: u32, payload: Bytes) -> Self {
+        Self {
+            stream_id,
+            body: FrameBody::Data(payload),
+        }
+    }
+
+    pub fn close(stream_id: u32) -> Self {
+        Self {
+            stream_id,
+            body: FrameBody::Close,
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        let (ty, payload): (u8, &[u8]) = match &self.body {
+            FrameBody::Open => (TYPE_OPEN, &[]),
+            FrameBody::Data(payload) => (TYPE_DATA, payload),
+            FrameBody::Close => (TYPE_CLOSE, &[]),
+        };
+        writer.write_u32(self.stream_id).await?;
+        writer.write_u8(ty).await?;
+        writer.write_u32(payload.len() as u32).await?;
+        writer.write_all(payload).await?;
+        writer.flush().await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Self> {
+        let stream_id = reader.read_u32().await?;
+        let ty = reader.read_u8().await?;
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame len {len} exceeds MAX_FRAME_LEN ({MAX_FRAME_LEN})"),
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        let body = match ty {
+            TYPE_OPEN => FrameBody::Open,
+            TYPE_DATA => FrameBody::Data(Bytes::from(payload)),
+            TYPE_CLOSE => FrameBody::Close,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown frame type {ty}"),
+                ))
+            }
+        };
+
+        Ok(Self { stream_id, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(frame: Frame) -> Frame {
+        let mut buf = Vec::new();
+        frame.write_to(&mut buf).await.unwrap();
+        let mut cursor = std::io::Cursor::new(buf);
+        Frame::read_from(&mut cursor).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_open() {
+        let decoded = round_trip(Frame::open(7)).await;
+        assert_eq!(decoded.stream_id, 7);
+        assert!(matches!(decoded.body, FrameBody::Open));
+    }
+
+    #[tokio::test]
+    async fn round_trips_data() {
+        let decoded = round_trip(Frame::data(3, Bytes::from_static(b"hello"))).await;
+        assert_eq!(decoded.stream_id, 3);
+        match decoded.body {
+            FrameBody::Data(payload) => assert_eq!(&payload[..], b"hello"),
+            other => panic!("expected Data, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_close() {
+        let decoded = round_trip(Frame::close(42)).await;
+        assert_eq!(decoded.stream_id, 42);
+        assert!(matches!(decoded.body, FrameBody::Close));
+    }
+}
@@ -0,0 +1,54 @@
+//! A small pool of already-handshaked [`Tunnel`]s.
+//!
+//! The server used to pair one `incoming_port` connection with whichever
+//! `proxy_port` connection happened to accept at the same instant, which is
+//! both racy and means every incoming connection waits on a fresh TLS
+//! handshake. Here the client keeps a handful of tunnels warm and the server
+//! just pops one off the front of the queue.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, Notify};
+
+use crate::tunnel::Tunnel;
+
+pub struct TunnelPool {
+    tunnels: Mutex<VecDeque<Arc<Tunnel>>>,
+    notify: Notify,
+}
+
+impl TunnelPool {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            tunnels: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+        })
+    }
+
+    pub async fn push(&self, tunnel: Arc<Tunnel>) {
+        self.tunnels.lock().await.push_back(tunnel);
+        self.notify.notify_one();
+    }
+
+    /// Pops the tunnel at the front of the queue, waiting up to `timeout` for
+    /// one to become available if the pool is currently empty. Dead tunnels
+    /// (their reader/writer tasks have already exited) are discarded rather
+    /// than handed out, so the pool self-heals instead of accumulating
+    /// poison entries that silently fail every stream opened on them.
+    pub async fn pop_with_timeout(&self, timeout: Duration) -> Option<Arc<Tunnel>> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                while let Some(tunnel) = self.tunnels.lock().await.pop_front() {
+                    if tunnel.is_alive() {
+                        return tunnel;
+                    }
+                }
+                self.notify.notified().await;
+            }
+        })
+        .await
+        .ok()
+    }
+}
@@ -0,0 +1,265 @@
+//! A [`Tunnel`] runs the frame protocol from [`crate::mux`] over a single
+//! underlying connection and hands out [`MuxStream`]s, each a logical stream
+//! that reads and writes like a normal `AsyncRead + AsyncWrite` socket. This
+//! lets `copy_bidirectional` be used exactly as before, just against a
+//! `MuxStream` instead of a raw `TcpStream`.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::PollSender;
+
+use crate::mux::{Frame, FrameBody};
+
+type StreamMap = Arc<Mutex<HashMap<u32, mpsc::Sender<Bytes>>>>;
+
+/// Per-stream and per-tunnel channel depth. There's no flow-control window
+/// on the wire, so a slow consumer on one stream backs up onto the shared
+/// reader/writer tasks (and so the whole tunnel) rather than growing memory
+/// without bound.
+const CHANNEL_BUFFER: usize = 64;
+
+/// One multiplexed tunnel. `accept_stream` yields streams the peer opened;
+/// `open_stream` asks the peer to open a new one on our behalf.
+///
+/// Caveat: there's no per-stream flow-control window on the wire, so the
+/// single reader task blocks on whichever stream's bounded `data_tx` is
+/// full. One slow consumer therefore head-of-line-blocks every other stream
+/// multiplexed on the same tunnel until it drains. Pool more than one tunnel
+/// if that's a problem for your workload.
+pub struct Tunnel {
+    frame_tx: mpsc::Sender<Frame>,
+    streams: StreamMap,
+    next_stream_id: AtomicU32,
+    opens_rx: Mutex<mpsc::Receiver<MuxStream>>,
+    alive: Arc<AtomicBool>,
+}
+
+impl Tunnel {
+    pub fn new<S>(stream: S) -> Arc<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+        let (frame_tx, mut frame_rx) = mpsc::channel::<Frame>(CHANNEL_BUFFER);
+        // Bounded: a peer that keeps opening streams nobody calls
+        // accept_stream() for (e.g. a server, which never does) applies
+        // backpressure to the reader task instead of growing memory
+        // without bound.
+        let (opens_tx, opens_rx) = mpsc::channel::<MuxStream>(CHANNEL_BUFFER);
+        let streams: StreamMap = Arc::new(Mutex::new(HashMap::new()));
+        let alive = Arc::new(AtomicBool::new(true));
+
+        // Dedicated writer task: every Data/Open/Close frame funnels through
+        // this mpsc so concurrent streams never interleave partial frames.
+        let alive_for_writer = alive.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = frame_rx.recv().await {
+                if frame.write_to(&mut write_half).await.is_err() {
+                    break;
+                }
+            }
+            alive_for_writer.store(false, Ordering::Relaxed);
+        });
+
+        let streams_for_reader = streams.clone();
+        let frame_tx_for_reader = frame_tx.clone();
+        let alive_for_reader = alive.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = match Frame::read_from(&mut read_half).await {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                };
+                match frame.body {
+                    FrameBody::Open => {
+                        // Register the stream's data channel *before*
+                        // handing it off, so a `Data` frame for it that
+                        // arrives on the very next read is never dropped
+                        // waiting for the receiving end to call `register`.
+                        let mux_stream = register(
+                            frame.stream_id,
+                            frame_tx_for_reader.clone(),
+                            &streams_for_reader,
+                        )
+                        .await;
+                        if opens_tx.send(mux_stream).await.is_err() {
+                            break;
+                        }
+                    }
+                    FrameBody::Data(payload) => {
+                        let tx = streams_for_reader.lock().await.get(&frame.stream_id).cloned();
+                        if let Some(tx) = tx {
+                            // Bounded channel: if the stream's reader isn't
+                            // keeping up this blocks the reader task, which
+                            // is the only backpressure this protocol has.
+                            let _ = tx.send(payload).await;
+                        }
+                    }
+                    FrameBody::Close => {
+                        streams_for_reader.lock().await.remove(&frame.stream_id);
+                    }
+                }
+            }
+            // Tunnel is gone: drop every live stream so their reads see EOF.
+            streams_for_reader.lock().await.clear();
+            alive_for_reader.store(false, Ordering::Relaxed);
+        });
+
+        Arc::new(Self {
+            frame_tx,
+            streams,
+            next_stream_id: AtomicU32::new(0),
+            opens_rx: Mutex::new(opens_rx),
+            alive,
+        })
+    }
+
+    /// Whether the reader and writer tasks are still running. A tunnel that
+    /// isn't alive will never yield another accepted stream and will never
+    /// actually send anything queued via `open_stream`; callers pulling
+    /// tunnels out of a pool should discard it instead of reusing it.
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the next stream the peer asked us to open. Returns `None`
+    /// once the tunnel has gone away.
+    pub async fn accept_stream(self: &Arc<Self>) -> Option<MuxStream> {
+        self.opens_rx.lock().await.recv().await
+    }
+
+    /// Allocates a new stream id, tells the peer to open it, and returns our
+    /// end of it.
+    pub async fn open_stream(self: &Arc<Self>) -> MuxStream {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let mux_stream = register(stream_id, self.frame_tx.clone(), &self.streams).await;
+        let _ = self.frame_tx.send(Frame::open(stream_id)).await;
+        mux_stream
+    }
+}
+
+/// Creates the data channel for `stream_id`, inserts it into `streams`, and
+/// wraps it as a [`MuxStream`]. Called both when we open a stream ourselves
+/// and, inline from the reader task, the instant an `Open` frame for a
+/// peer-initiated stream is read, so the map entry always exists before any
+/// `Data` frame for that stream can be processed.
+async fn register(stream_id: u32, frame_tx: mpsc::Sender<Frame>, streams: &StreamMap) -> MuxStream {
+    let (data_tx, data_rx) = mpsc::channel(CHANNEL_BUFFER);
+    streams.lock().await.insert(stream_id, data_tx);
+    MuxStream {
+        stream_id,
+        frame_tx: PollSender::new(frame_tx),
+        streams: streams.clone(),
+        data_rx,
+        read_buf: Bytes::new(),
+        closed: false,
+    }
+}
+
+/// One logical stream multiplexed over a [`Tunnel`]. Implements
+/// `AsyncRead`/`AsyncWrite` so it can be used with `copy_bidirectional` like
+/// any other socket.
+pub struct MuxStream {
+    stream_id: u32,
+    frame_tx: PollSender<Frame>,
+    streams: StreamMap,
+    data_rx: mpsc::Receiver<Bytes>,
+    read_buf: Bytes,
+    closed: bool,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let n = std::cmp::min(self.read_buf.len(), buf.remaining());
+                buf.put_slice(&self.read_buf[..n]);
+                self.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            match self.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(bytes)) => {
+                    self.read_buf = bytes;
+                    continue;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.frame_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                let frame = Frame::data(self.stream_id, Bytes::copy_from_slice(buf));
+                match self.frame_tx.send_item(frame) {
+                    Ok(()) => Poll::Ready(Ok(buf.len())),
+                    Err(_) => Poll::Ready(Err(tunnel_closed())),
+                }
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(tunnel_closed())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        match self.frame_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                self.closed = true;
+                let _ = self.frame_tx.send_item(Frame::close(self.stream_id));
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(_)) => {
+                self.closed = true;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn tunnel_closed() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "tunnel closed")
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Best-effort: a reserved-but-not-yet-sent poll_reserve would
+            // make get_ref() return None, in which case there's nothing
+            // more to do than let EOF propagate from the reader task.
+            if let Some(sender) = self.frame_tx.get_ref() {
+                let _ = sender.try_send(Frame::close(self.stream_id));
+            }
+        }
+        let streams = self.streams.clone();
+        let stream_id = self.stream_id;
+        tokio::spawn(async move {
+            streams.lock().await.remove(&stream_id);
+        });
+    }
+}
@@ -0,0 +1,339 @@
+//! Certificate verifiers, selected by [`PinMode`]:
+//!
+//! - [`SingleCertVerifier`] pins the exact DER bytes of one expected peer
+//!   certificate (the original behavior).
+//! - [`SpkiPinVerifier`] pins the SHA-256 fingerprint of the peer's
+//!   SubjectPublicKeyInfo instead, so a cert renewed with the same key still
+//!   validates, with an optional trust-on-first-use mode.
+//! - [`crate::dane::DaneVerifier`] checks the peer against TLSA records
+//!   published in DNS instead of a pinned value at all.
+//!
+//! [`PeerVerifier`] wraps whichever one is configured and implements both
+//! `ClientCertVerifier` and `ServerCertVerifier`, since either side of the
+//! tunnel may need to check the other's identity this way. DANE only makes
+//! sense for a client verifying the proxy it dials by name, so it's only
+//! ever constructed on that side.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::server::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::{Certificate, CertificateError, DistinguishedName, Error as TlsError};
+
+use crate::cert;
+use crate::dane::DaneVerifier;
+
+/// How the peer's identity should be established.
+pub enum PinMode {
+    /// Pin the exact bytes of the certificate at this path (the original
+    /// behavior).
+    FullCert(PathBuf),
+    /// Pin the SHA-256 hash of the peer's SubjectPublicKeyInfo.
+    SpkiSha256([u8; 32]),
+    /// Trust whichever SPKI fingerprint is seen first, record it at this
+    /// path, and refuse to proceed if it ever changes.
+    Tofu(PathBuf),
+    /// Validate against the TLSA records for `_<port>._tcp.<host>` instead
+    /// of any locally pinned value.
+    Dane { host: String, port: u16 },
+}
+
+impl PinMode {
+    pub fn from_args(
+        cert: Option<PathBuf>,
+        pin_spki_sha256: Option<String>,
+        tofu_pin_file: Option<PathBuf>,
+        dane_target: Option<(String, u16)>,
+    ) -> color_eyre::Result<Self> {
+        match (cert, pin_spki_sha256, tofu_pin_file, dane_target) {
+            (Some(path), None, None, None) => Ok(PinMode::FullCert(path)),
+            (None, Some(hex_fingerprint), None, None) => {
+                let bytes = hex::decode(hex_fingerprint.trim())?;
+                let fingerprint: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| eyre!("--pin-spki-sha256 must be 32 bytes (64 hex chars)"))?;
+                Ok(PinMode::SpkiSha256(fingerprint))
+            }
+            (None, None, Some(path), None) => Ok(PinMode::Tofu(path)),
+            (None, None, None, Some((host, port))) => Ok(PinMode::Dane { host, port }),
+            (None, None, None, None) => Err(eyre!(
+                "specify exactly one of the peer certificate, --pin-spki-sha256, --tofu-pin-file, or --verify dane"
+            )),
+            _ => Err(eyre!(
+                "the peer certificate, --pin-spki-sha256, --tofu-pin-file and --verify dane are mutually exclusive"
+            )),
+        }
+    }
+}
+
+pub enum PeerVerifier {
+    FullCert(SingleCertVerifier),
+    SpkiPin(SpkiPinVerifier),
+    Dane(DaneVerifier),
+}
+
+impl PeerVerifier {
+    pub fn new(mode: &PinMode) -> color_eyre::Result<Self> {
+        Ok(match mode {
+            PinMode::FullCert(path) => {
+                PeerVerifier::FullCert(SingleCertVerifier::new(cert::read_cert(path)?))
+            }
+            PinMode::SpkiSha256(fingerprint) => {
+                PeerVerifier::SpkiPin(SpkiPinVerifier::fixed(*fingerprint))
+            }
+            PinMode::Tofu(path) => PeerVerifier::SpkiPin(SpkiPinVerifier::tofu(path.clone())),
+            PinMode::Dane { host, port } => {
+                PeerVerifier::Dane(DaneVerifier::new(host.clone(), *port)?)
+            }
+        })
+    }
+}
+
+impl ClientCertVerifier for PeerVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        match self {
+            PeerVerifier::FullCert(v) => v.client_auth_root_subjects(),
+            PeerVerifier::SpkiPin(v) => v.client_auth_root_subjects(),
+            // DANE is only ever selected for the client's view of the
+            // proxy, never for the server's view of a client.
+            PeerVerifier::Dane(_) => &[],
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        match self {
+            PeerVerifier::FullCert(v) => v.verify_client_cert(end_entity, intermediates, now),
+            PeerVerifier::SpkiPin(v) => v.verify_client_cert(end_entity, intermediates, now),
+            PeerVerifier::Dane(_) => Err(TlsError::General(
+                "DANE verification is not supported for client certificates".into(),
+            )),
+        }
+    }
+}
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &tokio_rustls::rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        match self {
+            PeerVerifier::FullCert(v) => v.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            PeerVerifier::SpkiPin(v) => v.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            PeerVerifier::Dane(v) => v.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+        }
+    }
+}
+
+pub struct SingleCertVerifier {
+    certificate: Certificate,
+    distinguished_names: Vec<DistinguishedName>,
+}
+
+impl SingleCertVerifier {
+    pub fn new(certificate: Certificate) -> Self {
+        Self {
+            certificate,
+            distinguished_names: vec![DistinguishedName::from(vec![])],
+        }
+    }
+}
+
+impl ClientCertVerifier for SingleCertVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &self.distinguished_names
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        if end_entity == &self.certificate {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            Err(TlsError::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+impl ServerCertVerifier for SingleCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &tokio_rustls::rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        if end_entity == &self.certificate {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TlsError::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+/// Pins the SHA-256 hash of the peer's SubjectPublicKeyInfo rather than the
+/// whole certificate, either against a fixed fingerprint or
+/// trust-on-first-use against one recorded on disk.
+pub struct SpkiPinVerifier {
+    distinguished_names: Vec<DistinguishedName>,
+    source: PinSource,
+}
+
+enum PinSource {
+    Fixed([u8; 32]),
+    Tofu(PathBuf),
+}
+
+impl SpkiPinVerifier {
+    pub fn fixed(fingerprint: [u8; 32]) -> Self {
+        Self {
+            distinguished_names: vec![DistinguishedName::from(vec![])],
+            source: PinSource::Fixed(fingerprint),
+        }
+    }
+
+    pub fn tofu(path: PathBuf) -> Self {
+        Self {
+            distinguished_names: vec![DistinguishedName::from(vec![])],
+            source: PinSource::Tofu(path),
+        }
+    }
+
+    fn check(&self, end_entity: &Certificate) -> Result<(), TlsError> {
+        let actual = spki_sha256(end_entity)?;
+        match &self.source {
+            PinSource::Fixed(expected) => {
+                if constant_time_eq(&actual, expected) {
+                    Ok(())
+                } else {
+                    Err(TlsError::InvalidCertificate(
+                        CertificateError::ApplicationVerificationFailure,
+                    ))
+                }
+            }
+            PinSource::Tofu(path) => self.check_tofu(path, &actual),
+        }
+    }
+
+    fn check_tofu(&self, path: &Path, actual: &[u8; 32]) -> Result<(), TlsError> {
+        match std::fs::read_to_string(path) {
+            Ok(stored) => {
+                let stored = hex::decode(stored.trim()).map_err(|_| {
+                    TlsError::General(format!("{} does not contain a hex fingerprint", path.display()))
+                })?;
+                if constant_time_eq(actual, &stored) {
+                    Ok(())
+                } else {
+                    Err(TlsError::General(format!(
+                        "peer SPKI fingerprint changed from the one pinned in {}: expected {}, got {}",
+                        path.display(),
+                        hex::encode(&stored),
+                        hex::encode(actual),
+                    )))
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                // First time we've seen this peer: trust and remember it.
+                std::fs::write(path, hex::encode(actual))
+                    .map_err(|err| TlsError::General(format!("failed to record TOFU pin: {err}")))?;
+                Ok(())
+            }
+            Err(err) => Err(TlsError::General(format!(
+                "failed to read TOFU pin file {}: {err}",
+                path.display(),
+            ))),
+        }
+    }
+}
+
+impl ClientCertVerifier for SpkiPinVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &self.distinguished_names
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &tokio_rustls::rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Raw DER bytes of a certificate's SubjectPublicKeyInfo, used both for
+/// SPKI-fingerprint pinning here and for DANE selector-1 matching in
+/// [`crate::dane`].
+pub(crate) fn spki_der(end_entity: &Certificate) -> Result<Vec<u8>, TlsError> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&end_entity.0)
+        .map_err(|_| TlsError::InvalidCertificate(CertificateError::BadEncoding))?;
+    Ok(parsed.tbs_certificate.subject_pki.raw.to_vec())
+}
+
+fn spki_sha256(end_entity: &Certificate) -> Result<[u8; 32], TlsError> {
+    Ok(Sha256::digest(spki_der(end_entity)?).into())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}